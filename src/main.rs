@@ -7,9 +7,27 @@ fn main() {
         println!("{:?}", token);
     }
 
-    let tree = parse_node_tree(tokens).expect("Failed to parse tree");
+    let tree = parse_node_tree(file).expect("Failed to parse tree");
 
     println!("{:#?}", tree);
+
+    let xml = serialize_document(
+        &tree,
+        &WriteOptions::new().with_pretty(true).with_indent("    "),
+    );
+    println!("{}", xml);
+
+    let flat = parse_node_tree_with_config(
+        file,
+        &ParserConfig::new()
+            .with_trim_text(true)
+            .with_allow_multiple_roots(true)
+            .with_expected_root_count(1)
+            .with_flat_tree(true),
+    )
+    .expect("Failed to parse flat tree");
+
+    println!("{:#?}", flat);
 }
 
 #[derive(Debug)]
@@ -24,114 +42,769 @@ enum Node {
     Element(Element),
 }
 
+#[derive(Debug)]
+enum ParsedDocument {
+    Tree(Document),
+    Flat {
+        prolog: Option<Vec<Attribute>>,
+        nodes: Vec<FlatNode>,
+    },
+}
+
+#[derive(Debug)]
+struct FlatNode {
+    depth: usize,
+    parent: Option<usize>,
+    data: FlatNodeData,
+}
+
+#[derive(Debug)]
+enum FlatNodeData {
+    Text(String),
+    Element {
+        tag_name: String,
+        prefix: Option<String>,
+        local_name: String,
+        namespace: Option<String>,
+        attributes: Vec<ResolvedAttribute>,
+    },
+}
+
 #[derive(Debug)]
 struct Element {
     tag_name: String,
-    attributes: Vec<Attribute>,
+    prefix: Option<String>,
+    local_name: String,
+    namespace: Option<String>,
+    attributes: Vec<ResolvedAttribute>,
     children: Vec<Node>,
 }
 
-//TODO: use iterator
-fn parse_node_tree(tokens: Vec<Token>) -> Result<Document, String> {
-    let prolog: Option<Vec<Attribute>> = match tokens.first() {
-        Some(Token::Tag(tag)) if tag.name == "?xml" => Some(tag.attributes.clone()),
-        _ => None,
-    };
+#[derive(Debug, Clone)]
+struct ResolvedAttribute {
+    name: String,
+    prefix: Option<String>,
+    local_name: String,
+    namespace: Option<String>,
+    value: Option<String>,
+}
+
+struct WriteOptions {
+    pretty: bool,
+    indent: String,
+}
 
-    let mut tokens = tokens.into_iter();
-    if prolog.is_some() {
-        tokens.next();
+impl WriteOptions {
+    fn new() -> Self {
+        Self {
+            pretty: false,
+            indent: "  ".to_string(),
+        }
     }
 
-    let children = parse_node_tree_part(&mut tokens, 0, None)?;
-    let prolog = None;
-    Ok(Document { prolog, children })
+    fn with_pretty(mut self, pretty: bool) -> Self {
+        self.pretty = pretty;
+        self
+    }
+
+    fn with_indent(mut self, indent: impl Into<String>) -> Self {
+        self.indent = indent.into();
+        self
+    }
 }
 
-fn parse_node_tree_part<I>(
-    tokens: &mut I,
-    depth: usize,
-    current_tag_name: Option<&str>,
-) -> Result<Vec<Node>, String>
-where
-    I: Iterator<Item = Token>,
-{
-    let mut nodes = Vec::new();
+struct ParserConfig {
+    trim_text: bool,
+    allow_multiple_roots: bool,
+    expected_root_count: Option<usize>,
+    flat_tree: bool,
+}
 
-    while let Some(token) = tokens.next() {
-        match token {
-            Token::Text(text) => nodes.push(Node::Text(text)),
+impl ParserConfig {
+    fn new() -> Self {
+        Self {
+            trim_text: true,
+            allow_multiple_roots: false,
+            expected_root_count: None,
+            flat_tree: false,
+        }
+    }
 
-            Token::Tag(tag) => {
-                let TagToken {
-                    is_closing,
-                    name,
-                    attributes,
-                } = tag;
+    fn with_trim_text(mut self, trim_text: bool) -> Self {
+        self.trim_text = trim_text;
+        self
+    }
 
-                if name == "?xml" {
-                    return Err(format!(
-                        "Unexpected XML prolog. Prolog must occur at beginning of file"
-                    ));
+    fn with_allow_multiple_roots(mut self, allow_multiple_roots: bool) -> Self {
+        self.allow_multiple_roots = allow_multiple_roots;
+        self
+    }
+
+    fn with_expected_root_count(mut self, expected_root_count: usize) -> Self {
+        self.expected_root_count = Some(expected_root_count);
+        self
+    }
+
+    fn with_flat_tree(mut self, flat_tree: bool) -> Self {
+        self.flat_tree = flat_tree;
+        self
+    }
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn serialize_document(document: &Document, options: &WriteOptions) -> String {
+    let mut output = String::new();
+
+    if let Some(attributes) = &document.prolog {
+        output += "<?xml";
+        output += &serialize_attributes(attributes);
+        output += "?>";
+        if options.pretty {
+            output.push('\n');
+        }
+    }
+
+    for node in &document.children {
+        serialize_node(node, options, 0, &mut output);
+    }
+
+    output
+}
+
+fn serialize_node(node: &Node, options: &WriteOptions, depth: usize, output: &mut String) {
+    if options.pretty {
+        output.extend(std::iter::repeat(options.indent.as_str()).take(depth));
+    }
+
+    match node {
+        Node::Text(text) => *output += &escape_xml(text),
+
+        Node::Element(element) => {
+            output.push('<');
+            *output += &element.tag_name;
+            *output += &serialize_resolved_attributes(&element.attributes);
+
+            if element.children.is_empty() {
+                *output += " />";
+            } else {
+                output.push('>');
+                if options.pretty {
+                    output.push('\n');
+                }
+                for child in &element.children {
+                    serialize_node(child, options, depth + 1, output);
                 }
+                if options.pretty {
+                    output.extend(std::iter::repeat(options.indent.as_str()).take(depth));
+                }
+                *output += "</";
+                *output += &element.tag_name;
+                output.push('>');
+            }
+        }
+    }
 
-                if is_closing {
-                    if let Some(current) = current_tag_name {
-                        if current != name {
-                            return Err(format!(
-                                "Mismatched closing tag `</{}>. Does not match `<{}>`",
-                                name, current,
+    if options.pretty {
+        output.push('\n');
+    }
+}
+
+fn serialize_attributes(attributes: &[Attribute]) -> String {
+    let mut output = String::new();
+    for (key, value) in attributes {
+        output.push(' ');
+        output += key;
+        if let Some(value) = value {
+            output += "=\"";
+            output += &escape_xml(value);
+            output.push('"');
+        }
+    }
+    output
+}
+
+fn serialize_resolved_attributes(attributes: &[ResolvedAttribute]) -> String {
+    let mut output = String::new();
+    for attribute in attributes {
+        output.push(' ');
+        output += &attribute.name;
+        if let Some(value) = &attribute.value {
+            output += "=\"";
+            output += &escape_xml(value);
+            output.push('"');
+        }
+    }
+    output
+}
+
+fn escape_xml(input: &str) -> String {
+    let mut output = String::with_capacity(input.len());
+    for ch in input.chars() {
+        match ch {
+            '&' => output += "&amp;",
+            '<' => output += "&lt;",
+            '>' => output += "&gt;",
+            '\'' => output += "&apos;",
+            '"' => output += "&quot;",
+            _ => output.push(ch),
+        }
+    }
+    output
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug)]
+struct ParseError {
+    message: String,
+    position: Position,
+}
+
+impl ParseError {
+    fn new(position: Position, message: String) -> Self {
+        Self { message, position }
+    }
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (at line {}, column {})",
+            self.message, self.position.line, self.position.column
+        )
+    }
+}
+
+fn advance_position(mut pos: Position, ch: char) -> Position {
+    if ch == '\n' {
+        pos.line += 1;
+        pos.column = 1;
+    } else {
+        pos.column += 1;
+    }
+    pos
+}
+
+fn advance_position_str(mut pos: Position, consumed: &str) -> Position {
+    for ch in consumed.chars() {
+        pos = advance_position(pos, ch);
+    }
+    pos
+}
+
+fn parse_node_tree(input: &str) -> Result<Document, ParseError> {
+    match parse_node_tree_with_config(input, &ParserConfig::default())? {
+        ParsedDocument::Tree(document) => Ok(document),
+        ParsedDocument::Flat { .. } => {
+            unreachable!("ParserConfig::default() never requests a flat tree")
+        }
+    }
+}
+
+fn parse_node_tree_with_config(
+    input: &str,
+    config: &ParserConfig,
+) -> Result<ParsedDocument, ParseError> {
+    let mut reader = EventReader::with_trim_text(input, config.trim_text);
+
+    let mut prolog: Option<Vec<Attribute>> = None;
+    let mut roots: Vec<Node> = Vec::new();
+    let mut root_element_count = 0usize;
+    let mut stack: Vec<Element> = Vec::new();
+    let mut last_position = Position { line: 1, column: 1 };
+
+    while let Some(event) = reader.next() {
+        last_position = reader.pos;
+        match event? {
+            Event::ProcessingInstruction { name, attributes } => {
+                if name == "xml" && stack.is_empty() && root_element_count == 0 {
+                    prolog = Some(attributes);
+                }
+            }
+
+            Event::StartElement {
+                name,
+                prefix,
+                local_name,
+                namespace,
+                attributes,
+            } => {
+                stack.push(Element {
+                    tag_name: name,
+                    prefix,
+                    local_name,
+                    namespace,
+                    attributes,
+                    children: Vec::new(),
+                });
+            }
+
+            Event::EndElement { name } => {
+                let element = stack.pop().unwrap(); // Bruh
+                debug_assert_eq!(element.tag_name, name);
+
+                let node = Node::Element(element);
+                match stack.last_mut() {
+                    Some(parent) => parent.children.push(node),
+                    None => {
+                        let expects_multiple_roots = config.allow_multiple_roots
+                            || config.expected_root_count.is_some_and(|n| n > 1);
+                        if root_element_count > 0 && !expects_multiple_roots {
+                            return Err(ParseError::new(
+                                last_position,
+                                format!(
+                                    "Unexpected second root element `{}` (set ParserConfig::allow_multiple_roots to allow more than one)",
+                                    node_tag_name(&node)
+                                ),
                             ));
                         }
+                        root_element_count += 1;
+                        roots.push(node);
                     }
+                }
+            }
 
-                    if depth == 0 {
-                        return Err(format!(
-                            "Unexpected closing tag `</{}>. Expected end of file`",
-                            name
-                        ));
+            Event::Text(text) => match stack.last_mut() {
+                Some(parent) => parent.children.push(Node::Text(text)),
+                None => roots.push(Node::Text(text)),
+            },
+
+            Event::Comment(_) => {}
+
+            Event::Eof => break,
+        }
+    }
+
+    if let Some(expected) = config.expected_root_count {
+        if root_element_count != expected {
+            return Err(ParseError::new(
+                last_position,
+                format!(
+                    "Expected exactly {} top-level element(s), found {}",
+                    expected, root_element_count
+                ),
+            ));
+        }
+    }
+
+    if config.flat_tree {
+        Ok(ParsedDocument::Flat {
+            prolog,
+            nodes: flatten_roots(roots),
+        })
+    } else {
+        Ok(ParsedDocument::Tree(Document {
+            prolog,
+            children: roots,
+        }))
+    }
+}
+
+fn node_tag_name(node: &Node) -> &str {
+    match node {
+        Node::Element(element) => &element.tag_name,
+        Node::Text(_) => "#text",
+    }
+}
+
+fn flatten_roots(roots: Vec<Node>) -> Vec<FlatNode> {
+    let mut nodes = Vec::new();
+    for root in roots {
+        flatten_node(root, 0, None, &mut nodes);
+    }
+    nodes
+}
+
+fn flatten_node(node: Node, depth: usize, parent: Option<usize>, nodes: &mut Vec<FlatNode>) {
+    match node {
+        Node::Text(text) => {
+            nodes.push(FlatNode {
+                depth,
+                parent,
+                data: FlatNodeData::Text(text),
+            });
+        }
+        Node::Element(element) => {
+            let index = nodes.len();
+            nodes.push(FlatNode {
+                depth,
+                parent,
+                data: FlatNodeData::Element {
+                    tag_name: element.tag_name,
+                    prefix: element.prefix,
+                    local_name: element.local_name,
+                    namespace: element.namespace,
+                    attributes: element.attributes,
+                },
+            });
+            for child in element.children {
+                flatten_node(child, depth + 1, Some(index), nodes);
+            }
+        }
+    }
+}
+
+/// Events yielded by [`EventReader`], in document order.
+#[derive(Debug)]
+enum Event {
+    StartElement {
+        name: String,
+        prefix: Option<String>,
+        local_name: String,
+        namespace: Option<String>,
+        attributes: Vec<ResolvedAttribute>,
+    },
+    EndElement {
+        name: String,
+    },
+    Text(String),
+    Comment(String),
+    ProcessingInstruction {
+        name: String,
+        attributes: Vec<Attribute>,
+    },
+    Eof,
+}
+
+/// Pull parser that drives the lexer incrementally, without materializing
+/// the full token stream or node tree in memory.
+struct EventReader<'a> {
+    chars: std::str::Chars<'a>,
+    pos: Position,
+    current_token: String,
+    token_start: Position,
+    is_tag: bool,
+    is_comment: bool,
+    comment_content: String,
+    is_cdata: bool,
+    cdata_content: String,
+    open_tags: Vec<String>,
+    namespace_scopes: Vec<std::collections::HashMap<String, String>>,
+    pending: Option<Event>,
+    done: bool,
+    trim_text: bool,
+}
+
+fn split_name_prefix(name: &str) -> (Option<String>, String) {
+    match name.split_once(':') {
+        Some((prefix, local)) => (Some(prefix.to_string()), local.to_string()),
+        None => (None, name.to_string()),
+    }
+}
+
+impl<'a> EventReader<'a> {
+    fn new(input: &'a str) -> Self {
+        Self::with_trim_text(input, true)
+    }
+
+    fn with_trim_text(input: &'a str, trim_text: bool) -> Self {
+        let start = Position { line: 1, column: 1 };
+        Self {
+            chars: input.chars(),
+            pos: start,
+            current_token: String::new(),
+            token_start: start,
+            is_tag: false,
+            is_comment: false,
+            comment_content: String::new(),
+            is_cdata: false,
+            cdata_content: String::new(),
+            open_tags: Vec::new(),
+            namespace_scopes: Vec::new(),
+            pending: None,
+            done: false,
+            trim_text,
+        }
+    }
+
+    fn has_pending_text(&self) -> bool {
+        if self.trim_text {
+            !self.current_token.trim().is_empty()
+        } else {
+            !self.current_token.is_empty()
+        }
+    }
+
+    fn next_event(&mut self) -> Option<Result<Event, ParseError>> {
+        if let Some(event) = self.pending.take() {
+            return Some(Ok(event));
+        }
+        if self.done {
+            return None;
+        }
+
+        loop {
+            if self.is_cdata {
+                if self.chars.as_str().starts_with("]]>") {
+                    self.is_cdata = false;
+                    self.chars.nth("]]>".len() - 1);
+                    self.pos = advance_position_str(self.pos, "]]>");
+                    let text = std::mem::take(&mut self.cdata_content);
+                    return Some(Ok(Event::Text(text)));
+                }
+            } else if self.is_comment {
+                if self.chars.as_str().starts_with("-->") {
+                    self.is_comment = false;
+                    self.chars.nth("-->".len() - 1);
+                    self.pos = advance_position_str(self.pos, "-->");
+                    let text = std::mem::take(&mut self.comment_content);
+                    return Some(Ok(Event::Comment(text)));
+                }
+            } else if !self.is_tag
+                && self.has_pending_text()
+                && (self.chars.as_str().starts_with("<![CDATA[")
+                    || self.chars.as_str().starts_with("<!--"))
+            {
+                let text = parse_text(&self.current_token);
+                self.current_token = String::new();
+                self.token_start = self.pos;
+                return Some(Ok(Event::Text(text)));
+            } else if self.chars.as_str().starts_with("<![CDATA[") {
+                self.is_cdata = true;
+                self.chars.nth("<![CDATA[".len() - 1);
+                self.pos = advance_position_str(self.pos, "<![CDATA[");
+                continue;
+            } else if self.chars.as_str().starts_with("<!--") {
+                self.is_comment = true;
+                self.chars.nth("<!--".len() - 1);
+                self.pos = advance_position_str(self.pos, "<!--");
+                continue;
+            }
+
+            let Some(ch) = self.chars.next() else {
+                break;
+            };
+            let ch_pos = self.pos;
+            self.pos = advance_position(self.pos, ch);
+
+            match ch {
+                '<' if !self.is_comment && !self.is_cdata => {
+                    if self.is_tag {
+                        return Some(Err(ParseError::new(ch_pos, format!("Unexpected `<`"))));
+                    }
+                    self.is_tag = true;
+                    let text = self.has_pending_text().then(|| parse_text(&self.current_token));
+                    self.current_token = String::new();
+                    self.token_start = self.pos;
+                    if let Some(text) = text {
+                        return Some(Ok(Event::Text(text)));
                     }
-                    return Ok(nodes);
                 }
+                '>' if !self.is_comment && !self.is_cdata => {
+                    if !self.is_tag {
+                        return Some(Err(ParseError::new(ch_pos, format!("Unexpected `>`"))));
+                    }
+                    self.is_tag = false;
+                    if self.current_token.is_empty() {
+                        self.token_start = self.pos;
+                        continue;
+                    }
 
-                if depth == 0 && !nodes.is_empty() {
-                    return Err(format!(
-                        "Unexpected opening tag. Expected end of file. Only one root node is allowed"
-                    ));
+                    let tag_position = self.token_start;
+                    let tag = match parse_tag_token(&self.current_token, tag_position) {
+                        Ok(tag) => tag,
+                        Err(err) => return Some(Err(err)),
+                    };
+                    self.current_token = String::new();
+                    self.token_start = self.pos;
+
+                    return Some(self.handle_tag(tag, tag_position));
+                }
+                _ => {
+                    if self.is_cdata {
+                        self.cdata_content.push(ch);
+                    } else if self.is_comment {
+                        self.comment_content.push(ch);
+                    } else {
+                        self.current_token.push(ch);
+                    }
                 }
+            }
+        }
 
-                let children = parse_node_tree_part(tokens, depth + 1, Some(&name))?;
+        self.done = true;
 
-                let node = Node::Element(Element {
-                    tag_name: name,
-                    attributes,
-                    children,
-                });
-                nodes.push(node);
+        if self.is_cdata {
+            return Some(Err(ParseError::new(
+                self.pos,
+                format!("Unexpected end of file. Expected `]]>`"),
+            )));
+        }
+
+        if self.is_tag && !self.current_token.trim().is_empty() {
+            return Some(Err(ParseError::new(
+                self.token_start,
+                format!("Unexpected end of file. Expected `>`"),
+            )));
+        }
+
+        if !self.is_tag && self.has_pending_text() {
+            let text = parse_text(&self.current_token);
+            self.current_token = String::new();
+            return Some(Ok(Event::Text(text)));
+        }
+
+        if let Some(name) = self.open_tags.last() {
+            return Some(Err(ParseError::new(
+                self.pos,
+                format!("Unexpected end of file. Expected closing tag </{}>.", name),
+            )));
+        }
+
+        Some(Ok(Event::Eof))
+    }
+
+    fn handle_tag(&mut self, tag: TagToken, position: Position) -> Result<Event, ParseError> {
+        let TagToken {
+            is_closing,
+            is_self_closing,
+            name,
+            attributes,
+        } = tag;
+
+        if is_closing {
+            self.namespace_scopes.pop();
+            return match self.open_tags.pop() {
+                Some(current) if current == name => Ok(Event::EndElement { name }),
+                Some(current) => Err(ParseError::new(
+                    position,
+                    format!(
+                        "Mismatched closing tag `</{}>. Does not match `<{}>`",
+                        name, current,
+                    ),
+                )),
+                None => Err(ParseError::new(
+                    position,
+                    format!("Unexpected closing tag `</{}>. Expected end of file`", name),
+                )),
+            };
+        }
+
+        if let Some(target) = name.strip_prefix('?') {
+            return Ok(Event::ProcessingInstruction {
+                name: target.to_string(),
+                attributes,
+            });
+        }
+
+        let mut scope = self.namespace_scopes.last().cloned().unwrap_or_default();
+        for (key, value) in &attributes {
+            let Some(value) = value else { continue };
+            if key == "xmlns" {
+                scope.insert(String::new(), value.clone());
+            } else if let Some(prefix) = key.strip_prefix("xmlns:") {
+                scope.insert(prefix.to_string(), value.clone());
             }
         }
+
+        let (prefix, local_name) = split_name_prefix(&name);
+
+        let namespace = match &prefix {
+            Some(prefix) => match scope.get(prefix) {
+                Some(uri) => Some(uri.clone()),
+                None => {
+                    return Err(ParseError::new(
+                        position,
+                        format!("Unknown namespace prefix `{}` on element `<{}>`", prefix, name),
+                    ));
+                }
+            },
+            None => scope.get("").cloned(),
+        };
+
+        let mut resolved_attributes = Vec::with_capacity(attributes.len());
+        for (key, value) in attributes {
+            let (attr_prefix, attr_local_name) = split_name_prefix(&key);
+            let is_namespace_declaration = key == "xmlns" || key.starts_with("xmlns:");
+
+            // Unlike elements, an unprefixed attribute never inherits the
+            // default (no-prefix) namespace declaration. xmlns/xmlns:* are
+            // namespace declarations themselves, not namespaced attributes.
+            let attr_namespace = if is_namespace_declaration {
+                None
+            } else {
+                match &attr_prefix {
+                    Some(attr_prefix) => match scope.get(attr_prefix) {
+                        Some(uri) => Some(uri.clone()),
+                        None => {
+                            return Err(ParseError::new(
+                                position,
+                                format!(
+                                    "Unknown namespace prefix `{}` on attribute `{}`",
+                                    attr_prefix, key
+                                ),
+                            ));
+                        }
+                    },
+                    None => None,
+                }
+            };
+
+            resolved_attributes.push(ResolvedAttribute {
+                name: key,
+                prefix: attr_prefix,
+                local_name: attr_local_name,
+                namespace: attr_namespace,
+                value,
+            });
+        }
+
+        if !is_self_closing {
+            self.open_tags.push(name.clone());
+            self.namespace_scopes.push(scope);
+        } else {
+            self.pending = Some(Event::EndElement { name: name.clone() });
+        }
+
+        Ok(Event::StartElement {
+            name,
+            prefix,
+            local_name,
+            namespace,
+            attributes: resolved_attributes,
+        })
     }
+}
 
-    if depth > 0 {
-        // If depth > 0, then current_tag_name must be Some
-        let current = current_tag_name.unwrap(); // Bruh
-        return Err(format!(
-            "Unexpected end of file. Expected closing tag </{}>.",
-            current
-        ));
+impl<'a> Iterator for EventReader<'a> {
+    type Item = Result<Event, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event()
     }
+}
 
-    Ok(nodes)
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    position: Position,
 }
 
 #[derive(Debug)]
-enum Token {
+enum TokenKind {
     Text(String),
     Tag(TagToken),
 }
 
 struct TagToken {
     is_closing: bool,
+    is_self_closing: bool,
     name: String,
     attributes: Vec<Attribute>,
 }
@@ -142,64 +815,86 @@ impl std::fmt::Debug for TagToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "<{} {:?} {:?}>",
+            "<{}{} {:?} {:?}>",
             if self.is_closing { "/" } else { "" },
+            if self.is_self_closing { " /" } else { "" },
             self.name,
             self.attributes
         )
     }
 }
 
-fn parse_file(file: &str) -> Result<Vec<Token>, String> {
+fn parse_file(file: &str) -> Result<Vec<Token>, ParseError> {
     let mut tokens = Vec::new();
+    let mut reader = EventReader::new(file);
 
-    let mut current_token = String::new();
-    let mut is_tag = false;
-    let mut is_comment = false;
-
-    let mut chars = file.chars();
-    while let Some(ch) = chars.next() {
-        match ch {
-            '<' if !is_comment => {
-                if is_tag {
-                    return Err(format!("Unexpected `<`"));
-                }
-                is_tag = true;
-                if !current_token.trim().is_empty() {
-                    tokens.push(Token::Text(parse_text(&current_token)));
+    while let Some(event) = {
+        let position = reader.pos;
+        reader.next().map(|event| (event, position))
+    } {
+        let (event, position) = event;
+        match event? {
+            Event::StartElement {
+                name, attributes, ..
+            } => {
+                // EventReader collapses `<tag/>` into a StartElement plus a
+                // synthesized pending EndElement, losing the distinction
+                // Token/TagToken still needs. Reclaim it here rather than
+                // emitting two tokens for what was one self-closing tag.
+                let is_self_closing = matches!(
+                    &reader.pending,
+                    Some(Event::EndElement { name: pending_name }) if *pending_name == name
+                );
+                if is_self_closing {
+                    reader.pending.take();
                 }
-                current_token = String::new();
+
+                tokens.push(Token {
+                    kind: TokenKind::Tag(TagToken {
+                        is_closing: false,
+                        is_self_closing,
+                        name,
+                        attributes: attributes
+                            .into_iter()
+                            .map(|attribute| (attribute.name, attribute.value))
+                            .collect(),
+                    }),
+                    position,
+                });
             }
-            '>' if !is_comment => {
-                if !is_tag {
-                    return Err(format!("Unexpected `>`"));
-                }
-                is_tag = false;
-                if !current_token.is_empty() {
-                    tokens.push(Token::Tag(parse_tag_token(&current_token)?));
-                    current_token = String::new();
-                }
+            Event::EndElement { name } => {
+                tokens.push(Token {
+                    kind: TokenKind::Tag(TagToken {
+                        is_closing: true,
+                        is_self_closing: false,
+                        name,
+                        attributes: Vec::new(),
+                    }),
+                    position,
+                });
             }
-            _ => {
-                if chars.as_str().starts_with("<!--") {
-                    is_comment = true;
-                } else if chars.as_str().starts_with("-->") {
-                    is_comment = false;
-                    chars.nth("-->".len() - 1);
-                } else if !is_comment {
-                    current_token.push(ch);
-                }
+            Event::Text(text) => {
+                tokens.push(Token {
+                    kind: TokenKind::Text(text),
+                    position,
+                });
             }
+            Event::ProcessingInstruction { name, attributes } => {
+                tokens.push(Token {
+                    kind: TokenKind::Tag(TagToken {
+                        is_closing: false,
+                        is_self_closing: false,
+                        name: format!("?{}", name),
+                        attributes,
+                    }),
+                    position,
+                });
+            }
+            Event::Comment(_) => {}
+            Event::Eof => break,
         }
     }
 
-    if !current_token.trim().is_empty() {
-        if is_tag {
-            return Err(format!("Unexpected end of file. Expected `>`"));
-        }
-        tokens.push(Token::Text(parse_text(&current_token)));
-    }
-
     Ok(tokens)
 }
 
@@ -211,12 +906,14 @@ fn parse_text(input: &str) -> String {
         if let Some(ref mut entity) = current_entity {
             if ch == ';' || ch.is_whitespace() {
                 if let Some(entity_value) = parse_text_entity(entity) {
-                    output += entity_value;
+                    output += &entity_value;
                 } else {
                     eprintln!("[warning] unknown text entity `&{};`", entity);
                     output.push('&');
                     output += entity;
-                    output.push(';');
+                    if ch == ';' {
+                        output.push(';');
+                    }
                 }
                 if ch.is_whitespace() {
                     output.push(ch);
@@ -234,26 +931,47 @@ fn parse_text(input: &str) -> String {
         }
     }
 
+    if let Some(entity) = current_entity {
+        eprintln!("[warning] unterminated text entity `&{}`", entity);
+        output.push('&');
+        output += &entity;
+    }
+
     output
 }
 
-fn parse_text_entity(input: &str) -> Option<&'static str> {
-    // TODO: Hex codes etc.
+fn parse_text_entity(input: &str) -> Option<String> {
+    if let Some(digits) = input.strip_prefix('#') {
+        let code_point = if let Some(hex) = digits.strip_prefix('x').or(digits.strip_prefix('X')) {
+            u32::from_str_radix(hex, 16).ok()?
+        } else {
+            digits.parse::<u32>().ok()?
+        };
+        return Some(char::from_u32(code_point)?.to_string());
+    }
 
-    Some(match input {
-        "lt" => "<",
-        "gt" => ">",
-        "amp" => "&",
-        "apos" => "'",
-        "quot" => "\"",
+    Some(
+        match input {
+            "lt" => "<",
+            "gt" => ">",
+            "amp" => "&",
+            "apos" => "'",
+            "quot" => "\"",
 
-        _ => return None,
-    })
+            _ => return None,
+        }
+        .to_string(),
+    )
 }
 
-fn parse_tag_token(mut token: &str) -> Result<TagToken, String> {
+fn parse_tag_token(mut token: &str, start: Position) -> Result<TagToken, ParseError> {
+    let mut pos = start;
+
     if token.chars().next().is_some_and(|ch| ch.is_whitespace()) {
-        return Err(format!("Unexpected whitespace in tag `<{}>`", token));
+        return Err(ParseError::new(
+            pos,
+            format!("Unexpected whitespace in tag `<{}>`", token),
+        ));
     }
 
     let is_closing = token.starts_with('/');
@@ -261,45 +979,69 @@ fn parse_tag_token(mut token: &str) -> Result<TagToken, String> {
         let mut chars = token.chars();
         chars.next();
         token = chars.as_str();
+        pos = advance_position(pos, '/');
 
         if token.chars().next().is_some_and(|ch| ch.is_whitespace()) {
-            return Err(format!(
-                "Unexpected whitespace in tag `</{}>`, after backslash",
-                token
+            return Err(ParseError::new(
+                pos,
+                format!(
+                    "Unexpected whitespace in tag `</{}>`, after backslash",
+                    token
+                ),
             ));
         }
     }
 
+    let is_self_closing = token.trim_end().ends_with('/');
+    if is_self_closing {
+        token = token.trim_end();
+        token = &token[..token.len() - 1];
+    }
+
+    if token.starts_with('?') && token.trim_end().ends_with('?') {
+        token = token.trim_end();
+        token = &token[..token.len() - 1];
+    }
+
     let (name, attributes) = match token.find(' ') {
         Some(index) => {
             let (name, attr_str) = token.split_at(index);
-            (name, parse_tag_attributes(attr_str)?)
+            let attr_start = advance_position_str(pos, name);
+            (name, parse_tag_attributes(attr_str, attr_start)?)
         }
         None => (token, Vec::new()),
     };
 
     Ok(TagToken {
         is_closing,
+        is_self_closing,
         name: name.to_string(),
         attributes,
     })
 }
 
-fn parse_tag_attributes(string: &str) -> Result<Vec<Attribute>, String> {
+fn parse_tag_attributes(string: &str, start: Position) -> Result<Vec<Attribute>, ParseError> {
     let mut attrs = Vec::<Attribute>::new();
 
     let mut key_opt: Option<(bool, String)> = None;
     let mut value_opt: Option<(char, String)> = None;
+    let mut pos = start;
 
     let mut chars = string.chars();
     while let Some(ch) = chars.next() {
+        let ch_pos = pos;
+        pos = advance_position(pos, ch);
+
         match key_opt {
             None => {
                 if ch.is_whitespace() {
                     continue;
                 }
                 if ch == '=' {
-                    return Err(format!("Unexpected `=`. Expected start of attribute key"));
+                    return Err(ParseError::new(
+                        ch_pos,
+                        format!("Unexpected `=`. Expected start of attribute key"),
+                    ));
                 }
                 key_opt = Some((false, ch.to_string()));
             }
@@ -324,6 +1066,9 @@ fn parse_tag_attributes(string: &str) -> Result<Vec<Attribute>, String> {
 
                     let quote = loop {
                         let ch = chars.next();
+                        if let Some(ch) = ch {
+                            pos = advance_position(pos, ch);
+                        }
                         if ch.is_some_and(|ch| ch.is_whitespace()) {
                             continue;
                         }
@@ -331,10 +1076,16 @@ fn parse_tag_attributes(string: &str) -> Result<Vec<Attribute>, String> {
                     };
 
                     let Some(quote) = quote else {
-                        return Err(format!("Unexpected end of tag. Expected `'` or `\"`"));
+                        return Err(ParseError::new(
+                            pos,
+                            format!("Unexpected end of tag. Expected `'` or `\"`"),
+                        ));
                     };
                     if quote != '"' && quote != '\'' {
-                        return Err(format!("Unexpected `{}`. Expected `'` or `\"`", quote));
+                        return Err(ParseError::new(
+                            pos,
+                            format!("Unexpected `{}`. Expected `'` or `\"`", quote),
+                        ));
                     }
 
                     value_opt = Some((quote, String::new()));
@@ -361,13 +1112,15 @@ fn parse_tag_attributes(string: &str) -> Result<Vec<Attribute>, String> {
         attrs.push((key, None));
     }
     if value_opt.is_some() {
-        return Err(format!("Unexpected end of tag. Expected `'` or `\"`"));
+        return Err(ParseError::new(
+            pos,
+            format!("Unexpected end of tag. Expected `'` or `\"`"),
+        ));
     }
 
     Ok(attrs)
 }
 
-fn parse_tag_attribute_value(string: &str) -> Result<String, String> {
-    // TODO: Replace escape characters
-    Ok(string.to_string())
+fn parse_tag_attribute_value(string: &str) -> Result<String, ParseError> {
+    Ok(parse_text(string))
 }